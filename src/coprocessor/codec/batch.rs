@@ -0,0 +1,312 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Physical, per-column storage used by batch executors. See `BatchExecuteResult` in
+//! `dag::batch::interface` for the physical/logical distinction.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use arrow::array::{Array, BinaryArray};
+use arrow::datatypes::{DataType, Field, Schema as ArrowSchema};
+use arrow::record_batch::RecordBatch;
+use protobuf::Message;
+use tipb::expression::FieldType;
+
+use crate::coprocessor::Error;
+
+/// Key under which a column's original TiKV `FieldType` (hex-encoded protobuf bytes) is stored
+/// in the corresponding Arrow `Field`'s metadata by `arrow_schema_for_field_types`.
+const FIELD_TYPE_METADATA_KEY: &str = "tikv_field_type_hex";
+
+/// One physical column: every row's value, stored in its original (possibly still encoded)
+/// form, indexed by physical row offset.
+#[derive(Clone, Default)]
+pub struct LazyBatchColumn {
+    field_type: FieldType,
+    raw_data: Vec<Vec<u8>>,
+    /// Running total of `raw_data[i].len()`, kept up to date incrementally in `push_raw` so
+    /// that sizing a batch by bytes (see `BatchSizeHint`) doesn't need to re-walk every row.
+    approximate_size: usize,
+}
+
+impl LazyBatchColumn {
+    pub fn new(field_type: FieldType) -> Self {
+        LazyBatchColumn {
+            field_type,
+            raw_data: Vec::new(),
+            approximate_size: 0,
+        }
+    }
+
+    pub fn field_type(&self) -> &FieldType {
+        &self.field_type
+    }
+
+    pub fn raw(&self) -> &[Vec<u8>] {
+        &self.raw_data
+    }
+
+    pub fn push_raw(&mut self, datum: Vec<u8>) {
+        self.approximate_size += datum.len();
+        self.raw_data.push(datum);
+    }
+
+    pub fn len(&self) -> usize {
+        self.raw_data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.raw_data.is_empty()
+    }
+
+    /// A cheap running estimate of this column's encoded size, in bytes.
+    pub fn approximate_mem_size(&self) -> usize {
+        self.approximate_size
+    }
+}
+
+/// Columnar storage for one batch, in physical row order.
+#[derive(Clone, Default)]
+pub struct LazyBatchColumnVec {
+    columns: Vec<LazyBatchColumn>,
+}
+
+impl LazyBatchColumnVec {
+    pub fn empty() -> Self {
+        LazyBatchColumnVec::default()
+    }
+
+    pub fn with_columns(columns: Vec<LazyBatchColumn>) -> Self {
+        LazyBatchColumnVec { columns }
+    }
+
+    pub fn columns(&self) -> &[LazyBatchColumn] {
+        &self.columns
+    }
+
+    pub fn rows_len(&self) -> usize {
+        self.columns.first().map_or(0, LazyBatchColumn::len)
+    }
+
+    /// A cheap running estimate of the encoded size of all physical columns, in bytes. See
+    /// `BatchSizeHint` in `dag::batch::interface`.
+    pub fn approximate_mem_size(&self) -> usize {
+        self.columns.iter().map(LazyBatchColumn::approximate_mem_size).sum()
+    }
+
+    /// Builds a new `LazyBatchColumnVec` made up of exactly the given physical rows, in the
+    /// given order. Unlike cloning the whole `LazyBatchColumnVec`, this only copies the rows
+    /// that are actually selected, so splitting a batch across `N` partitions copies the
+    /// original data once in total rather than `N` times.
+    pub fn project_physical_rows(&self, physical_rows: &[usize]) -> LazyBatchColumnVec {
+        let columns = self
+            .columns
+            .iter()
+            .map(|column| {
+                let mut projected = LazyBatchColumn::new(column.field_type.clone());
+                for &row in physical_rows {
+                    projected.push_raw(column.raw_data[row].clone());
+                }
+                projected
+            })
+            .collect();
+        LazyBatchColumnVec { columns }
+    }
+}
+
+impl std::ops::Index<usize> for LazyBatchColumnVec {
+    type Output = LazyBatchColumn;
+
+    fn index(&self, index: usize) -> &LazyBatchColumn {
+        &self.columns[index]
+    }
+}
+
+/// Arrow IPC (de)serialization. Every column is written as Arrow `Binary`, i.e. its raw,
+/// possibly-still-encoded bytes are carried through verbatim; the column's original TiKV
+/// `FieldType` rides along in the Arrow field's metadata (rather than being reconstructed from
+/// Arrow's own, coarser type system) so that a restored column decodes identically to the one
+/// that was spilled.
+impl LazyBatchColumnVec {
+    /// Builds the Arrow schema that `to_arrow_record_batch` encodes into and
+    /// `from_arrow_record_batch` decodes from.
+    pub fn arrow_schema_for_field_types(schema: &[FieldType]) -> ArrowSchema {
+        let fields = schema
+            .iter()
+            .enumerate()
+            .map(|(i, field_type)| {
+                let mut metadata = HashMap::new();
+                metadata.insert(
+                    FIELD_TYPE_METADATA_KEY.to_string(),
+                    to_hex(
+                        &field_type
+                            .write_to_bytes()
+                            .expect("FieldType is always serializable"),
+                    ),
+                );
+                Field::new(&format!("c{}", i), DataType::Binary, true)
+                    .with_metadata(Some(metadata))
+            })
+            .collect();
+        ArrowSchema::new(fields)
+    }
+
+    /// Encodes the rows named by `logical_rows`, in logical order, as a single Arrow record
+    /// batch. Rows filtered out of the logical view are never included, so spilling never
+    /// writes data that has already been filtered away.
+    pub fn to_arrow_record_batch(
+        &self,
+        arrow_schema: &ArrowSchema,
+        logical_rows: &[usize],
+    ) -> Result<RecordBatch, Error> {
+        let arrays = self
+            .columns
+            .iter()
+            .map(|column| {
+                let values = logical_rows
+                    .iter()
+                    .map(|&row| column.raw_data[row].as_slice());
+                Arc::new(BinaryArray::from_iter_values(values)) as Arc<dyn Array>
+            })
+            .collect();
+        RecordBatch::try_new(Arc::new(arrow_schema.clone()), arrays)
+            .map_err(|e| Error::Other(format!("build arrow record batch: {}", e).into()))
+    }
+
+    /// The inverse of `to_arrow_record_batch`: rebuilds a `LazyBatchColumnVec` from a record
+    /// batch previously produced by it, restoring each column's `FieldType` from the record
+    /// batch's own schema metadata rather than from an external, possibly out-of-sync schema.
+    pub fn from_arrow_record_batch(record_batch: &RecordBatch) -> Result<LazyBatchColumnVec, Error> {
+        let schema = record_batch.schema();
+        let mut columns = Vec::with_capacity(record_batch.num_columns());
+        for i in 0..record_batch.num_columns() {
+            let field = schema.field(i);
+            let field_type_hex = field
+                .metadata()
+                .as_ref()
+                .and_then(|metadata| metadata.get(FIELD_TYPE_METADATA_KEY))
+                .ok_or_else(|| {
+                    Error::Other("spilled column is missing its FieldType metadata".to_string().into())
+                })?;
+            let field_type = FieldType::parse_from_bytes(&from_hex(field_type_hex)?)
+                .map_err(|e| Error::Other(format!("decode FieldType metadata: {}", e).into()))?;
+            let array = record_batch
+                .column(i)
+                .as_any()
+                .downcast_ref::<BinaryArray>()
+                .ok_or_else(|| {
+                    Error::Other("spilled column is not binary-encoded".to_string().into())
+                })?;
+            let mut column = LazyBatchColumn::new(field_type);
+            for row in 0..array.len() {
+                column.push_raw(array.value(row).to_vec());
+            }
+            columns.push(column);
+        }
+        Ok(LazyBatchColumnVec { columns })
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The inverse of `to_hex`. Unlike a lossy "best effort" decode, any malformed digit is reported
+/// as an error rather than silently treated as `0`: this metadata is the only record of a
+/// spilled column's `FieldType`, so a corrupted blob must fail loudly instead of quietly
+/// restoring a column with the wrong type.
+fn from_hex(hex: &str) -> Result<Vec<u8>, Error> {
+    if hex.len() % 2 != 0 {
+        return Err(Error::Other(
+            format!("corrupt FieldType metadata: odd-length hex string {:?}", hex).into(),
+        ));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16).map_err(|e| {
+                Error::Other(format!("corrupt FieldType metadata: {} in {:?}", e, hex).into())
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec_of(rows: &[&[u8]]) -> LazyBatchColumnVec {
+        let mut column = LazyBatchColumn::new(FieldType::new());
+        for row in rows {
+            column.push_raw(row.to_vec());
+        }
+        LazyBatchColumnVec::with_columns(vec![column])
+    }
+
+    #[test]
+    fn project_physical_rows_keeps_only_selected_rows_in_order() {
+        let original = vec_of(&[b"a", b"b", b"c", b"d"]);
+        let projected = original.project_physical_rows(&[3, 1]);
+        assert_eq!(projected.rows_len(), 2);
+        assert_eq!(projected[0].raw(), &[b"d".to_vec(), b"b".to_vec()]);
+    }
+
+    #[test]
+    fn project_physical_rows_does_not_mutate_the_source() {
+        let original = vec_of(&[b"a", b"b"]);
+        let _ = original.project_physical_rows(&[0]);
+        assert_eq!(original.rows_len(), 2);
+    }
+
+    #[test]
+    fn approximate_mem_size_tracks_pushed_rows() {
+        let mut column = LazyBatchColumn::new(FieldType::new());
+        assert_eq!(column.approximate_mem_size(), 0);
+        column.push_raw(vec![0; 3]);
+        column.push_raw(vec![0; 5]);
+        assert_eq!(column.approximate_mem_size(), 8);
+
+        let columns = LazyBatchColumnVec::with_columns(vec![column.clone(), column]);
+        assert_eq!(columns.approximate_mem_size(), 16);
+    }
+
+    #[test]
+    fn from_hex_rejects_malformed_digits_instead_of_guessing() {
+        assert!(from_hex("zz").is_err());
+        assert!(from_hex("abc").is_err()); // odd length
+        assert_eq!(from_hex("68656c6c6f").unwrap(), b"hello".to_vec());
+    }
+
+    #[test]
+    fn from_arrow_record_batch_rejects_corrupt_field_type_metadata() {
+        let mut metadata = HashMap::new();
+        metadata.insert(FIELD_TYPE_METADATA_KEY.to_string(), "not-hex!!".to_string());
+        let field = Field::new("c0", DataType::Binary, true).with_metadata(Some(metadata));
+        let arrow_schema = ArrowSchema::new(vec![field]);
+        let array = Arc::new(BinaryArray::from_iter_values(vec![b"x".as_ref()])) as Arc<dyn Array>;
+        let record_batch = RecordBatch::try_new(Arc::new(arrow_schema), vec![array]).unwrap();
+
+        assert!(LazyBatchColumnVec::from_arrow_record_batch(&record_batch).is_err());
+    }
+
+    #[test]
+    fn arrow_round_trip_preserves_bytes_and_field_type() {
+        let mut field_type = FieldType::new();
+        field_type.set_tp(3); // arbitrary, just needs to survive the round trip
+
+        let mut column = LazyBatchColumn::new(field_type.clone());
+        column.push_raw(b"hello".to_vec());
+        column.push_raw(b"world".to_vec());
+        let original = LazyBatchColumnVec::with_columns(vec![column]);
+
+        let arrow_schema = LazyBatchColumnVec::arrow_schema_for_field_types(&[field_type.clone()]);
+        let record_batch = original
+            .to_arrow_record_batch(&arrow_schema, &[1, 0])
+            .unwrap();
+        let restored = LazyBatchColumnVec::from_arrow_record_batch(&record_batch).unwrap();
+
+        assert_eq!(restored.rows_len(), 2);
+        assert_eq!(restored[0].raw(), &[b"world".to_vec(), b"hello".to_vec()]);
+        assert_eq!(restored[0].field_type().get_tp(), field_type.get_tp());
+    }
+}
@@ -0,0 +1,63 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Cooperative cancellation for batch executors.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A cheaply cloneable flag that lets the caller of an executor tree (e.g. because a request's
+/// deadline has passed, or it was killed) ask every executor in the tree to stop at the next
+/// batch boundary, without having to thread a `&mut` reference through the whole tree.
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken(Arc::new(AtomicBool::new(false)))
+    }
+
+    /// Requests cancellation. Idempotent.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    /// Whether cancellation has been requested. Checked by executors at batch boundaries, i.e.
+    /// once per `next_batch()` call rather than once per row.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_out_not_cancelled() {
+        assert!(!CancellationToken::new().is_cancelled());
+        assert!(!CancellationToken::default().is_cancelled());
+    }
+
+    #[test]
+    fn cancel_is_observed_by_is_cancelled() {
+        let token = CancellationToken::new();
+        token.cancel();
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn cancel_is_idempotent() {
+        let token = CancellationToken::new();
+        token.cancel();
+        token.cancel();
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn clones_share_the_same_cancellation_state() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        clone.cancel();
+        assert!(token.is_cancelled());
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Drives a single executor tree and interleaves metadata flushes into its data stream, so that
+//! a long-running request can report partial progress without waiting for the whole response.
+
+use super::interface::{BatchExecuteStatistics, BatchExecutor, BatchOutput};
+
+/// Wraps the root of an executor tree and turns its `next_batch_or_meta()` calls into a single
+/// ordered stream of `BatchOutput` items, emitting a `Meta` item of its own every time at least
+/// `flush_stats_every_rows` logical rows have been produced since the last flush, and once more
+/// right after the executor tree drains so that the tail of the request (fewer rows than the
+/// threshold) is never dropped on the floor.
+///
+/// There is exactly one producer (whoever calls `next()`) and the interleaving happens
+/// synchronously in that call, so the consumer is never made to wait for more than one
+/// `next_batch_or_meta()` worth of work to learn about new progress.
+pub struct BatchCoordinator<E: BatchExecutor> {
+    executor: E,
+    flush_stats_every_rows: usize,
+    rows_since_last_flush: usize,
+    /// Set once the executor tree has reported `is_drained != Ok(false)`.
+    drained: bool,
+    /// Whether the mandatory post-drain flush has been delivered yet.
+    final_flush_pending: bool,
+}
+
+impl<E: BatchExecutor> BatchCoordinator<E> {
+    pub fn new(executor: E, flush_stats_every_rows: usize) -> Self {
+        BatchCoordinator {
+            executor,
+            flush_stats_every_rows,
+            rows_since_last_flush: 0,
+            drained: false,
+            final_flush_pending: false,
+        }
+    }
+
+    /// Pulls the next item of the stream: either a data batch from the executor tree, or a
+    /// metadata item describing the progress made since the last one was emitted.
+    ///
+    /// Once the executor tree has drained, the very next call always returns the mandatory
+    /// final `Meta` flush, carrying whatever progress had accumulated since the last one.
+    /// Calling `next()` again after that final flush just keeps returning empty `Meta` items:
+    /// the stream is over and there is nothing left to report.
+    pub fn next(&mut self, scan_rows: usize) -> BatchOutput {
+        if self.final_flush_pending {
+            self.final_flush_pending = false;
+            return BatchOutput::Meta(self.flush_statistics());
+        }
+
+        if self.drained {
+            return BatchOutput::Meta(BatchExecuteStatistics::new(1));
+        }
+
+        if self.flush_stats_every_rows > 0
+            && self.rows_since_last_flush >= self.flush_stats_every_rows
+        {
+            return BatchOutput::Meta(self.flush_statistics());
+        }
+
+        match self.executor.next_batch_or_meta(scan_rows) {
+            BatchOutput::Data(result) => {
+                self.rows_since_last_flush += result.logical_rows.len();
+                if !matches!(result.is_drained, Ok(false)) {
+                    self.drained = true;
+                    self.final_flush_pending = true;
+                }
+                BatchOutput::Data(result)
+            }
+            meta @ BatchOutput::Meta(_) => meta,
+        }
+    }
+
+    /// Collects and clears the coordinator's accumulated statistics right now, regardless of
+    /// `flush_stats_every_rows`. `pub` so a caller can request an out-of-band flush (e.g. when
+    /// giving up on a request early) in addition to the automatic threshold- and drain-triggered
+    /// ones.
+    pub fn flush_statistics(&mut self) -> BatchExecuteStatistics {
+        self.rows_since_last_flush = 0;
+        let mut statistics = BatchExecuteStatistics::new(1);
+        self.executor.collect_statistics(&mut statistics);
+        statistics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coprocessor::codec::batch::LazyBatchColumnVec;
+    use crate::coprocessor::dag::batch::interface::BatchExecuteResult;
+    use crate::coprocessor::dag::expr::EvalWarnings;
+    use std::collections::VecDeque;
+    use tipb::expression::FieldType;
+
+    struct ScriptedExecutor {
+        results: VecDeque<BatchExecuteResult>,
+    }
+
+    impl BatchExecutor for ScriptedExecutor {
+        fn schema(&self) -> &[FieldType] {
+            &[]
+        }
+
+        fn next_batch(&mut self, _scan_rows: usize) -> BatchExecuteResult {
+            self.results.pop_front().expect("no more scripted results")
+        }
+
+        fn collect_statistics(&mut self, _destination: &mut BatchExecuteStatistics) {}
+    }
+
+    fn data(rows: usize, is_drained: Result<bool, crate::coprocessor::Error>) -> BatchExecuteResult {
+        BatchExecuteResult {
+            physical_columns: LazyBatchColumnVec::empty(),
+            logical_rows: (0..rows).collect(),
+            warnings: EvalWarnings::default(),
+            is_drained,
+        }
+    }
+
+    #[test]
+    fn flushes_meta_once_the_row_threshold_is_crossed() {
+        let executor = ScriptedExecutor {
+            results: VecDeque::from(vec![data(5, Ok(false)), data(5, Ok(true))]),
+        };
+        let mut coordinator = BatchCoordinator::new(executor, 5);
+
+        assert!(matches!(coordinator.next(10), BatchOutput::Data(_)));
+        // 5 rows produced, threshold is 5: the next pull must be the flush, not more data.
+        assert!(matches!(coordinator.next(10), BatchOutput::Meta(_)));
+    }
+
+    #[test]
+    fn flushes_a_final_meta_after_the_executor_drains_even_under_threshold() {
+        // Total row count (2) never reaches the flush threshold (100), so without an explicit
+        // post-drain flush no statistics would ever be emitted through this stream.
+        let executor = ScriptedExecutor {
+            results: VecDeque::from(vec![data(2, Ok(true))]),
+        };
+        let mut coordinator = BatchCoordinator::new(executor, 100);
+
+        assert!(matches!(coordinator.next(10), BatchOutput::Data(_)));
+        assert!(matches!(coordinator.next(10), BatchOutput::Meta(_)));
+    }
+
+    #[test]
+    fn flush_statistics_is_callable_directly() {
+        let executor = ScriptedExecutor {
+            results: VecDeque::new(),
+        };
+        let mut coordinator = BatchCoordinator::new(executor, 100);
+        let _ = coordinator.flush_statistics();
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! A leaf executor backed by an in-memory table of already-encoded rows. It is the one
+//! executor in this tree that actually honors `BatchSizeHint::max_bytes`: rows are appended to
+//! `physical_columns` one at a time, checking the running byte-size estimate after each one, so
+//! the batch stops as soon as it has reached or crossed the byte budget (the row that crosses it
+//! is included) rather than always filling to `hint.rows`.
+
+use tipb::expression::FieldType;
+
+use crate::coprocessor::codec::batch::{LazyBatchColumn, LazyBatchColumnVec};
+use crate::coprocessor::dag::batch::interface::*;
+use crate::coprocessor::dag::expr::EvalWarnings;
+
+pub struct BatchMemScanExecutor {
+    schema: Vec<FieldType>,
+    rows: Vec<Vec<Vec<u8>>>,
+    cursor: usize,
+}
+
+impl BatchMemScanExecutor {
+    pub fn new(schema: Vec<FieldType>, rows: Vec<Vec<Vec<u8>>>) -> Self {
+        BatchMemScanExecutor {
+            schema,
+            rows,
+            cursor: 0,
+        }
+    }
+}
+
+impl BatchExecutor for BatchMemScanExecutor {
+    fn schema(&self) -> &[FieldType] {
+        &self.schema
+    }
+
+    fn next_batch(&mut self, scan_rows: usize) -> BatchExecuteResult {
+        self.next_batch_sized(BatchSizeHint {
+            rows: scan_rows,
+            max_bytes: 0,
+        })
+    }
+
+    fn next_batch_sized(&mut self, hint: BatchSizeHint) -> BatchExecuteResult {
+        let mut columns: Vec<LazyBatchColumn> = self
+            .schema
+            .iter()
+            .map(|field_type| LazyBatchColumn::new(field_type.clone()))
+            .collect();
+        let mut row_count = 0;
+
+        while row_count < hint.rows && self.cursor < self.rows.len() {
+            // Always take at least one row so a single row wider than the budget doesn't stall
+            // the scan forever.
+            if hint.max_bytes > 0
+                && row_count > 0
+                && columns
+                    .iter()
+                    .map(LazyBatchColumn::approximate_mem_size)
+                    .sum::<usize>()
+                    >= hint.max_bytes
+            {
+                break;
+            }
+
+            let row = &self.rows[self.cursor];
+            for (column, datum) in columns.iter_mut().zip(row) {
+                column.push_raw(datum.clone());
+            }
+            self.cursor += 1;
+            row_count += 1;
+        }
+
+        let logical_rows = (0..row_count).collect();
+        let is_drained = Ok(self.cursor >= self.rows.len());
+        BatchExecuteResult {
+            physical_columns: LazyBatchColumnVec::with_columns(columns),
+            logical_rows,
+            warnings: EvalWarnings::default(),
+            is_drained,
+        }
+    }
+
+    fn collect_statistics(&mut self, _destination: &mut BatchExecuteStatistics) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(count: usize, row_bytes: usize) -> Vec<Vec<Vec<u8>>> {
+        (0..count).map(|_| vec![vec![0u8; row_bytes]]).collect()
+    }
+
+    #[test]
+    fn next_batch_sized_ignores_max_bytes_when_zero() {
+        let mut executor = BatchMemScanExecutor::new(vec![FieldType::new()], rows(10, 100));
+        let result = executor.next_batch_sized(BatchSizeHint {
+            rows: 10,
+            max_bytes: 0,
+        });
+        assert_eq!(result.logical_rows.len(), 10);
+    }
+
+    #[test]
+    fn next_batch_sized_stops_once_the_byte_budget_is_crossed() {
+        let mut executor = BatchMemScanExecutor::new(vec![FieldType::new()], rows(10, 100));
+        let result = executor.next_batch_sized(BatchSizeHint {
+            rows: 10,
+            max_bytes: 250,
+        });
+        // 3 rows * 100 bytes = 300 >= 250, so the 3rd row is the last one included.
+        assert_eq!(result.logical_rows.len(), 3);
+        assert!(matches!(result.is_drained, Ok(false)));
+    }
+
+    #[test]
+    fn next_batch_sized_always_includes_at_least_one_row() {
+        let mut executor = BatchMemScanExecutor::new(vec![FieldType::new()], rows(10, 1000));
+        let result = executor.next_batch_sized(BatchSizeHint {
+            rows: 10,
+            max_bytes: 1,
+        });
+        assert_eq!(result.logical_rows.len(), 1);
+    }
+
+    #[test]
+    fn next_batch_sized_drains_when_the_table_is_exhausted() {
+        let mut executor = BatchMemScanExecutor::new(vec![FieldType::new()], rows(2, 10));
+        let first = executor.next_batch_sized(BatchSizeHint {
+            rows: 10,
+            max_bytes: 0,
+        });
+        assert!(matches!(first.is_drained, Ok(true)));
+        assert_eq!(first.logical_rows.len(), 2);
+    }
+}
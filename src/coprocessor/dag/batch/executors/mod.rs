@@ -0,0 +1,9 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Concrete implementations of `BatchExecutor`.
+
+mod mem_scan;
+mod repartition;
+
+pub use self::mem_scan::BatchMemScanExecutor;
+pub use self::repartition::{BatchRepartitionExecutor, PartitionScheme};
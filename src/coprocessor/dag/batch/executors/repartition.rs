@@ -0,0 +1,488 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! An executor that fans the logical rows of its child out across several downstream
+//! partitions, mirroring `RepartitionExec` in DataFusion.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, TryLockError};
+use std::time::Duration;
+
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender};
+use tipb::expression::FieldType;
+
+use crate::coprocessor::codec::batch::LazyBatchColumnVec;
+use crate::coprocessor::dag::batch::interface::*;
+use crate::coprocessor::dag::expr::EvalWarnings;
+use crate::coprocessor::Error;
+
+/// The channel capacity of each output partition. Small on purpose: the whole point of
+/// repartitioning is to overlap producer and consumers, not to buffer unbounded work.
+const PARTITION_CHANNEL_CAPACITY: usize = 2;
+
+/// The row count requested from the child on each round. Repartitioning does not change how
+/// many rows the child is asked to produce, only how the result is distributed afterwards.
+const CHILD_BATCH_ROWS: usize = 1024;
+
+/// How often `next_batch_checked` re-checks cancellation while it would otherwise block waiting
+/// for its own channel or for a turn to become the producer.
+const CANCELLATION_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How logical rows of a `BatchExecuteResult` are assigned to output partitions.
+#[derive(Clone)]
+pub enum PartitionScheme {
+    /// Contiguous slices of `logical_rows` are assigned to output partitions in turn.
+    RoundRobin,
+    /// Each logical row is routed to `hash(row[column_indices]) % partition_count`.
+    Hash { column_indices: Vec<usize> },
+}
+
+/// State shared by all partitions of one repartition point. Exactly one partition at a time
+/// drives the child executor; the others simply wait on their channel.
+struct Shared<Src: BatchExecutor> {
+    child: Mutex<Src>,
+    /// Set once the child has returned `Ok(true)` or `Err(_)`. Checked before taking `child`'s
+    /// lock so that a drained child is never polled again.
+    drained: AtomicBool,
+    senders: Vec<Sender<BatchExecuteResult>>,
+    scheme: PartitionScheme,
+}
+
+/// One output partition of a repartitioned pipeline.
+///
+/// All partitions of the same repartition point share a `Shared<Src>`. Whichever partition's
+/// `next_batch()` is called when its own channel is empty becomes the producer for that round:
+/// it pulls one batch from the child, slices it according to `scheme`, and pushes one message
+/// (possibly carrying zero rows) onto every partition's channel, itself included.
+pub struct BatchRepartitionExecutor<Src: BatchExecutor> {
+    schema: Vec<FieldType>,
+    index: usize,
+    receiver: Receiver<BatchExecuteResult>,
+    shared: Arc<Shared<Src>>,
+}
+
+impl<Src: BatchExecutor> BatchRepartitionExecutor<Src> {
+    /// Builds `partition_count` executors that together consume `child`'s output according to
+    /// `scheme`. The returned `Vec` is in partition order.
+    pub fn new_partitions(child: Src, partition_count: usize, scheme: PartitionScheme) -> Vec<Self> {
+        assert!(partition_count > 0);
+
+        let schema = child.schema().to_vec();
+        let mut receivers = Vec::with_capacity(partition_count);
+        let mut senders = Vec::with_capacity(partition_count);
+        for _ in 0..partition_count {
+            let (tx, rx) = channel::bounded(PARTITION_CHANNEL_CAPACITY);
+            senders.push(tx);
+            receivers.push(rx);
+        }
+
+        let shared = Arc::new(Shared {
+            child: Mutex::new(child),
+            drained: AtomicBool::new(false),
+            senders,
+            scheme,
+        });
+
+        receivers
+            .into_iter()
+            .enumerate()
+            .map(|(index, receiver)| BatchRepartitionExecutor {
+                schema: schema.clone(),
+                index,
+                receiver,
+                shared: shared.clone(),
+            })
+            .collect()
+    }
+
+    /// Pulls one batch from the child and splits it into one (possibly empty) result per output
+    /// partition, marking `shared.drained` if the child has no more data. The caller is
+    /// responsible for delivering the returned results to `self.shared.senders` *after* releasing
+    /// the lock on `child` — see `next_batch` for why that split matters.
+    fn produce_one_round(&self, child: &mut Src) -> Vec<BatchExecuteResult> {
+        let partition_count = self.shared.senders.len();
+        let result = child.next_batch(CHILD_BATCH_ROWS);
+        let is_drained = result.is_drained;
+        let physical_columns = result.physical_columns;
+        let logical_rows = result.logical_rows;
+        let warnings = result.warnings;
+
+        if !matches!(is_drained, Ok(false)) {
+            self.shared.drained.store(true, Ordering::Release);
+        }
+
+        let mut per_partition_rows: Vec<Vec<usize>> = vec![Vec::new(); partition_count];
+        match &self.shared.scheme {
+            PartitionScheme::RoundRobin => {
+                let chunk_size =
+                    (logical_rows.len() + partition_count - 1) / partition_count.max(1);
+                for (partition, rows) in logical_rows.chunks(chunk_size.max(1)).enumerate() {
+                    per_partition_rows[partition].extend_from_slice(rows);
+                }
+            }
+            PartitionScheme::Hash { column_indices } => {
+                for &row in &logical_rows {
+                    let partition = hash_row(&physical_columns, column_indices, row) as usize
+                        % partition_count;
+                    per_partition_rows[partition].push(row);
+                }
+            }
+        }
+
+        // `Error` is not `Clone`, so every partition gets its own error re-derived from the
+        // original. `Cancelled` round-trips as itself so that callers can still special-case
+        // cancellation past a repartition boundary; anything else is re-derived from the
+        // original's `Display` message, which is the best we can do generically.
+        let make_is_drained: Box<dyn Fn() -> Result<bool, Error>> = match is_drained {
+            Ok(v) => Box::new(move || Ok(v)),
+            Err(Error::Cancelled) => Box::new(|| Err(Error::Cancelled)),
+            Err(e) => {
+                let message = format!("{}", e);
+                Box::new(move || Err(Error::Other(message.clone().into())))
+            }
+        };
+
+        per_partition_rows
+            .into_iter()
+            .enumerate()
+            .map(|(partition, rows)| BatchExecuteResult {
+                // Projects only this partition's own rows out of the child's batch, rather than
+                // cloning the whole batch into every partition: splitting one batch across `N`
+                // partitions should not multiply the in-memory working set by `N`.
+                physical_columns: physical_columns.project_physical_rows(&rows),
+                logical_rows: (0..rows.len()).collect(),
+                // Attributing the whole batch's warnings to partition 0 only avoids reporting
+                // the same warning `partition_count` times to the user.
+                warnings: if partition == 0 {
+                    warnings.clone()
+                } else {
+                    EvalWarnings::default()
+                },
+                is_drained: make_is_drained(),
+            })
+            .collect()
+    }
+
+    /// Delivers one produced round to every partition's channel. Must only be called after the
+    /// lock on `shared.child` has already been released: delivery blocks on a partition whose
+    /// channel is full, i.e. a consumer that has fallen behind or stopped polling entirely, and
+    /// doing that while still holding the child lock would wedge every other partition too (none
+    /// of them could become the next producer, nor rely on a message that will now never arrive).
+    fn deliver_one_round(&self, results: Vec<BatchExecuteResult>) {
+        for (partition, result) in results.into_iter().enumerate() {
+            // This only fails once that partition's `BatchRepartitionExecutor` (and with it the
+            // receiving end) has been dropped; its share of the round is then discarded.
+            let _ = self.shared.senders[partition].send(result);
+        }
+    }
+}
+
+fn hash_row(columns: &LazyBatchColumnVec, column_indices: &[usize], row: usize) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for &column_index in column_indices {
+        columns[column_index].raw()[row].hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+impl<Src: BatchExecutor> BatchExecutor for BatchRepartitionExecutor<Src> {
+    fn schema(&self) -> &[FieldType] {
+        &self.schema
+    }
+
+    fn next_batch(&mut self, _scan_rows: usize) -> BatchExecuteResult {
+        loop {
+            if let Ok(result) = self.receiver.try_recv() {
+                return result;
+            }
+
+            if self.shared.drained.load(Ordering::Acquire) {
+                // The child is already drained. Our terminal message, if not picked up above,
+                // must still be in flight from the round that drained it; block for it.
+                return self.receiver.recv().unwrap_or_else(|_| BatchExecuteResult {
+                    physical_columns: LazyBatchColumnVec::empty(),
+                    logical_rows: Vec::new(),
+                    warnings: EvalWarnings::default(),
+                    is_drained: Ok(true),
+                });
+            }
+
+            let produced = {
+                let mut child = self.shared.child.lock().unwrap();
+                // Re-check: another partition may have drained the child while we were waiting
+                // for the lock, in which case there is nothing left for us to produce.
+                if self.shared.drained.load(Ordering::Acquire) {
+                    None
+                } else {
+                    Some(self.produce_one_round(&mut child))
+                }
+            };
+            // The lock is released above, *before* delivering: see `deliver_one_round`.
+            if let Some(results) = produced {
+                self.deliver_one_round(results);
+            }
+        }
+    }
+
+    /// Same as `next_batch`, except cancellation is re-checked every time this call would
+    /// otherwise sit idle waiting for its own channel or for a turn to become the producer, so a
+    /// cancelled request notices promptly instead of only at the next call's entry.
+    fn next_batch_checked(
+        &mut self,
+        _scan_rows: usize,
+        cancellation: &CancellationToken,
+    ) -> BatchExecuteResult {
+        loop {
+            if cancellation.is_cancelled() {
+                return BatchExecuteResult {
+                    physical_columns: LazyBatchColumnVec::empty(),
+                    logical_rows: Vec::new(),
+                    warnings: EvalWarnings::default(),
+                    is_drained: Err(Error::Cancelled),
+                };
+            }
+
+            if let Ok(result) = self.receiver.try_recv() {
+                return result;
+            }
+
+            if self.shared.drained.load(Ordering::Acquire) {
+                match self.receiver.recv_timeout(CANCELLATION_POLL_INTERVAL) {
+                    Ok(result) => return result,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        return BatchExecuteResult {
+                            physical_columns: LazyBatchColumnVec::empty(),
+                            logical_rows: Vec::new(),
+                            warnings: EvalWarnings::default(),
+                            is_drained: Ok(true),
+                        };
+                    }
+                }
+            }
+
+            // `try_lock` rather than `lock`: another partition may be mid-round, and blocking
+            // here would stop us from ever re-checking cancellation until it is our turn.
+            let produced = match self.shared.child.try_lock() {
+                Ok(mut child) => {
+                    if self.shared.drained.load(Ordering::Acquire) {
+                        None
+                    } else {
+                        Some(self.produce_one_round(&mut child))
+                    }
+                }
+                Err(TryLockError::WouldBlock) => None,
+                Err(TryLockError::Poisoned(e)) => panic!("child executor panicked: {}", e),
+            };
+
+            match produced {
+                Some(results) => self.deliver_one_round(results),
+                // Not our turn to produce and nothing waiting for us locally yet; briefly back
+                // off before re-checking cancellation and our channel again.
+                None => std::thread::sleep(CANCELLATION_POLL_INTERVAL),
+            }
+        }
+    }
+
+    fn collect_statistics(&mut self, destination: &mut BatchExecuteStatistics) {
+        // Only partition 0 forwards the child's statistics, otherwise a single child batch
+        // would be counted once per output partition.
+        if self.index == 0 {
+            self.shared.child.lock().unwrap().collect_statistics(destination);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::coprocessor::codec::batch::{LazyBatchColumn, LazyBatchColumnVec};
+
+    /// A child executor that simply replays a fixed sequence of results, one per `next_batch()`
+    /// call, panicking if it is called more times than results were supplied.
+    struct ScriptedExecutor {
+        schema: Vec<FieldType>,
+        results: VecDeque<BatchExecuteResult>,
+    }
+
+    impl BatchExecutor for ScriptedExecutor {
+        fn schema(&self) -> &[FieldType] {
+            &self.schema
+        }
+
+        fn next_batch(&mut self, _scan_rows: usize) -> BatchExecuteResult {
+            self.results
+                .pop_front()
+                .expect("ScriptedExecutor called more times than it has results for")
+        }
+
+        fn collect_statistics(&mut self, _destination: &mut BatchExecuteStatistics) {}
+    }
+
+    fn single_column_batch(values: &[u8], is_drained: Result<bool, Error>) -> BatchExecuteResult {
+        let mut column = LazyBatchColumn::new(FieldType::new());
+        for &v in values {
+            column.push_raw(vec![v]);
+        }
+        let logical_rows: Vec<usize> = (0..values.len()).collect();
+        BatchExecuteResult {
+            physical_columns: LazyBatchColumnVec::with_columns(vec![column]),
+            logical_rows,
+            warnings: EvalWarnings::default(),
+            is_drained,
+        }
+    }
+
+    fn collect_all(executors: &mut [BatchRepartitionExecutor<ScriptedExecutor>]) -> Vec<Vec<u8>> {
+        let mut per_partition = vec![Vec::new(); executors.len()];
+        loop {
+            let mut any_pending = false;
+            for (partition, executor) in executors.iter_mut().enumerate() {
+                let result = executor.next_batch(1024);
+                for &row in &result.logical_rows {
+                    per_partition[partition].push(result.physical_columns[0].raw()[row][0]);
+                }
+                if matches!(result.is_drained, Ok(false)) {
+                    any_pending = true;
+                }
+            }
+            if !any_pending {
+                break;
+            }
+        }
+        per_partition
+    }
+
+    #[test]
+    fn round_robin_splits_contiguous_slices_across_partitions() {
+        let child = ScriptedExecutor {
+            schema: vec![FieldType::new()],
+            results: VecDeque::from(vec![single_column_batch(&[1, 2, 3, 4], Ok(true))]),
+        };
+        let mut partitions =
+            BatchRepartitionExecutor::new_partitions(child, 2, PartitionScheme::RoundRobin);
+        let result = collect_all(&mut partitions);
+        assert_eq!(result[0], vec![1, 2]);
+        assert_eq!(result[1], vec![3, 4]);
+    }
+
+    #[test]
+    fn hash_partitioning_is_deterministic_and_covers_every_row() {
+        let child = ScriptedExecutor {
+            schema: vec![FieldType::new()],
+            results: VecDeque::from(vec![single_column_batch(&[1, 2, 3, 4, 5], Ok(true))]),
+        };
+        let mut partitions = BatchRepartitionExecutor::new_partitions(
+            child,
+            3,
+            PartitionScheme::Hash {
+                column_indices: vec![0],
+            },
+        );
+        let result = collect_all(&mut partitions);
+        let mut all_rows: Vec<u8> = result.into_iter().flatten().collect();
+        all_rows.sort();
+        assert_eq!(all_rows, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn error_is_forwarded_to_every_partition() {
+        let child = ScriptedExecutor {
+            schema: vec![FieldType::new()],
+            results: VecDeque::from(vec![single_column_batch(
+                &[],
+                Err(Error::Other("boom".to_string().into())),
+            )]),
+        };
+        let mut partitions =
+            BatchRepartitionExecutor::new_partitions(child, 3, PartitionScheme::RoundRobin);
+        for executor in &mut partitions {
+            let result = executor.next_batch(1024);
+            assert!(result.is_drained.is_err());
+        }
+    }
+
+    #[test]
+    fn cancellation_round_trips_as_itself_to_every_partition() {
+        let child = ScriptedExecutor {
+            schema: vec![FieldType::new()],
+            results: VecDeque::from(vec![single_column_batch(&[], Err(Error::Cancelled))]),
+        };
+        let mut partitions =
+            BatchRepartitionExecutor::new_partitions(child, 2, PartitionScheme::RoundRobin);
+        for executor in &mut partitions {
+            let result = executor.next_batch(1024);
+            match result.is_drained {
+                Err(e) => assert!(e.is_cancelled()),
+                Ok(_) => panic!("expected an error"),
+            }
+        }
+    }
+
+    /// Regression test for the deadlock where the elected producer blocked on a full (capacity
+    /// `PARTITION_CHANNEL_CAPACITY`) channel send for a lagging partition *while still holding
+    /// `shared.child`'s lock*, which then wedged every other partition too. Drives two partitions
+    /// from independent threads, one starting well after the other, far more rounds than the channel
+    /// capacity, and fails (via a timeout) instead of hanging forever if the deadlock regresses.
+    #[test]
+    fn staggered_consumption_does_not_deadlock() {
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::Duration;
+
+        let round_count: u8 = 10;
+        assert!((round_count as usize) > PARTITION_CHANNEL_CAPACITY);
+
+        let results = (0..round_count)
+            .map(|i| single_column_batch(&[2 * i, 2 * i + 1], Ok(i + 1 == round_count)))
+            .collect();
+        let child = ScriptedExecutor {
+            schema: vec![FieldType::new()],
+            results,
+        };
+        let mut partitions =
+            BatchRepartitionExecutor::new_partitions(child, 2, PartitionScheme::RoundRobin);
+        let mut slow = partitions.pop().unwrap();
+        let mut fast = partitions.pop().unwrap();
+
+        let drain = |executor: &mut BatchRepartitionExecutor<ScriptedExecutor>| {
+            let mut collected = Vec::new();
+            loop {
+                let result = executor.next_batch(1024);
+                for &row in &result.logical_rows {
+                    collected.push(result.physical_columns[0].raw()[row][0]);
+                }
+                if !matches!(result.is_drained, Ok(false)) {
+                    break;
+                }
+            }
+            collected
+        };
+
+        let fast_handle = thread::spawn(move || drain(&mut fast));
+        let slow_handle = thread::spawn(move || {
+            // Give the fast partition a head start so it races far enough ahead to overflow the
+            // other partition's bounded channel before the slow side ever polls.
+            thread::sleep(Duration::from_millis(50));
+            drain(&mut slow)
+        });
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let fast_rows = fast_handle.join().unwrap();
+            let slow_rows = slow_handle.join().unwrap();
+            let _ = tx.send((fast_rows, slow_rows));
+        });
+
+        let (fast_rows, slow_rows) = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("deadlocked: the lagging partition never received its rows");
+
+        let mut all_rows: Vec<u8> = fast_rows.into_iter().chain(slow_rows).collect();
+        all_rows.sort();
+        assert_eq!(all_rows, (0..2 * round_count).collect::<Vec<u8>>());
+    }
+}
@@ -5,6 +5,7 @@
 //! Batch executor common structures.
 
 pub use super::super::exec_summary::{ExecSummaryCollector, WithSummaryCollector};
+pub use super::cancellation::CancellationToken;
 pub use super::statistics::BatchExecuteStatistics;
 
 use tipb::expression::FieldType;
@@ -30,6 +31,33 @@ pub trait BatchExecutor: Send {
     /// See `is_drained` in `BatchExecuteResult`.
     fn next_batch(&mut self, scan_rows: usize) -> BatchExecuteResult;
 
+    /// Pulls next several rows of data, stopping early once the accumulated physical columns
+    /// are estimated to reach `hint.max_bytes`, even if fewer than `hint.rows` rows were
+    /// produced.
+    ///
+    /// `scan_rows` alone drives batches purely by row count, which is a poor proxy for memory
+    /// footprint: a batch of a dozen wide `BLOB` columns and a batch of a single `TINYINT`
+    /// column need wildly different row counts to land in, say, the same 256KB of L2 cache per
+    /// core. Leaf (scan) executors that produce `physical_columns` directly should honor
+    /// `hint.max_bytes` using `LazyBatchColumnVec`'s running byte-size estimate; executors that
+    /// merely forward or transform a child's batch can rely on the default implementation,
+    /// which ignores `max_bytes` and degrades to the row-count-only behavior of `next_batch()`.
+    fn next_batch_sized(&mut self, hint: BatchSizeHint) -> BatchExecuteResult {
+        self.next_batch(hint.rows)
+    }
+
+    /// Pulls either the next data batch or a metadata item describing progress made so far
+    /// (execution summaries, scanned-key counts, warnings), whichever becomes available first.
+    ///
+    /// This lets a long-running request stream partial statistics and warnings to the caller
+    /// mid-flight instead of only when the whole response is assembled, without the producer
+    /// blocking on more input than it needs to. Executors that have no metadata of their own to
+    /// interleave, which is most of them, can rely on the default implementation: it simply
+    /// wraps `next_batch()`'s result in `BatchOutput::Data`.
+    fn next_batch_or_meta(&mut self, scan_rows: usize) -> BatchOutput {
+        BatchOutput::Data(self.next_batch(scan_rows))
+    }
+
     /// Collects statistics (including but not limited to metrics and execution summaries)
     /// accumulated during execution and prepares for next collection.
     ///
@@ -41,6 +69,29 @@ pub trait BatchExecutor: Send {
     /// this function is less than `next_batch()`.
     fn collect_statistics(&mut self, destination: &mut BatchExecuteStatistics);
 
+    /// Pulls next several rows of data, but first checks `cancellation` and returns
+    /// `Error::Cancelled` immediately (without touching the child) if it has been requested.
+    ///
+    /// Cancellation is only checked at this batch boundary, not per row, so an in-flight
+    /// `next_batch()` call still runs to completion; this bounds how long a query that exceeded
+    /// its deadline or was killed keeps running to roughly one batch, instead of running to
+    /// completion.
+    fn next_batch_checked(
+        &mut self,
+        scan_rows: usize,
+        cancellation: &CancellationToken,
+    ) -> BatchExecuteResult {
+        if cancellation.is_cancelled() {
+            return BatchExecuteResult {
+                physical_columns: LazyBatchColumnVec::empty(),
+                logical_rows: Vec::new(),
+                warnings: EvalWarnings::default(),
+                is_drained: Err(Error::Cancelled),
+            };
+        }
+        self.next_batch(scan_rows)
+    }
+
     fn with_summary_collector<C: ExecSummaryCollector + Send>(
         self,
         summary_collector: C,
@@ -53,6 +104,20 @@ pub trait BatchExecutor: Send {
             inner: self,
         }
     }
+
+    /// Wraps `self` so that any `Err(_)` surfacing from its `is_drained` is annotated with
+    /// `operator_name`, the way `with_summary_collector` wraps an executor to also collect
+    /// summaries. This lets a deep executor tree report which stage actually failed instead of
+    /// only the underlying cause.
+    fn with_operator_context(self, operator_name: &'static str) -> WithOperatorContext<Self>
+    where
+        Self: Sized,
+    {
+        WithOperatorContext {
+            operator_name,
+            inner: self,
+        }
+    }
 }
 
 impl<T: BatchExecutor + ?Sized> BatchExecutor for Box<T> {
@@ -64,9 +129,25 @@ impl<T: BatchExecutor + ?Sized> BatchExecutor for Box<T> {
         (**self).next_batch(scan_rows)
     }
 
+    fn next_batch_sized(&mut self, hint: BatchSizeHint) -> BatchExecuteResult {
+        (**self).next_batch_sized(hint)
+    }
+
+    fn next_batch_or_meta(&mut self, scan_rows: usize) -> BatchOutput {
+        (**self).next_batch_or_meta(scan_rows)
+    }
+
     fn collect_statistics(&mut self, destination: &mut BatchExecuteStatistics) {
         (**self).collect_statistics(destination)
     }
+
+    fn next_batch_checked(
+        &mut self,
+        scan_rows: usize,
+        cancellation: &CancellationToken,
+    ) -> BatchExecuteResult {
+        (**self).next_batch_checked(scan_rows, cancellation)
+    }
 }
 
 impl<C: ExecSummaryCollector + Send, T: BatchExecutor> BatchExecutor
@@ -91,6 +172,68 @@ impl<C: ExecSummaryCollector + Send, T: BatchExecutor> BatchExecutor
     }
 }
 
+/// Annotates any error produced by `inner` with the name of the operator that produced it. See
+/// `BatchExecutor::with_operator_context`.
+pub struct WithOperatorContext<T> {
+    operator_name: &'static str,
+    inner: T,
+}
+
+impl<T: BatchExecutor> BatchExecutor for WithOperatorContext<T> {
+    fn schema(&self) -> &[FieldType] {
+        self.inner.schema()
+    }
+
+    fn next_batch(&mut self, scan_rows: usize) -> BatchExecuteResult {
+        let mut result = self.inner.next_batch(scan_rows);
+        result.is_drained = self.annotate(result.is_drained);
+        result
+    }
+
+    fn next_batch_checked(
+        &mut self,
+        scan_rows: usize,
+        cancellation: &CancellationToken,
+    ) -> BatchExecuteResult {
+        let mut result = self.inner.next_batch_checked(scan_rows, cancellation);
+        result.is_drained = self.annotate(result.is_drained);
+        result
+    }
+
+    fn collect_statistics(&mut self, destination: &mut BatchExecuteStatistics) {
+        self.inner.collect_statistics(destination)
+    }
+}
+
+impl<T> WithOperatorContext<T> {
+    fn annotate(&self, is_drained: Result<bool, Error>) -> Result<bool, Error> {
+        is_drained.map_err(|e| e.attach_operator(self.operator_name))
+    }
+}
+
+/// Bounds on the size of a single batch passed to `next_batch_sized()`.
+///
+/// `rows` is an upper bound on the row count, same as the `scan_rows` argument of
+/// `next_batch()`. `max_bytes` is an upper bound on the estimated encoded size of the
+/// *physical* columns accumulated for the batch; a value of `0` means "no byte limit", so that
+/// `BatchSizeHint { rows, max_bytes: 0 }` behaves exactly like `next_batch(rows)`.
+#[derive(Clone, Copy)]
+pub struct BatchSizeHint {
+    pub rows: usize,
+    pub max_bytes: usize,
+}
+
+/// One item of the stream produced by `next_batch_or_meta()` (and by `BatchCoordinator::next()`):
+/// either a data batch or a metadata item that was ready to be flushed before the next data batch.
+///
+/// Each call yields exactly one item. When metadata is ready, it is returned as a `Meta` item in
+/// place of a data batch, so that it is not delayed behind a data batch that is still to be
+/// computed; the data then follows on later calls.
+pub enum BatchOutput {
+    Data(BatchExecuteResult),
+    Meta(BatchExecuteStatistics),
+}
+
 /// Data to be flowed between parent and child executors' single `next_batch()` invocation.
 ///
 /// Note: there are other data flow between executors, like metrics and output statistics.
@@ -139,3 +282,101 @@ pub struct BatchExecuteResult {
     // explain what it is. We can change it to a better name or use an enum if necessary.
     pub is_drained: Result<bool, Error>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An executor that always returns one fixed, non-empty result and never drains, so tests
+    /// can tell whether `next_batch_checked` actually reached `next_batch` or short-circuited.
+    struct AlwaysPendingExecutor;
+
+    impl BatchExecutor for AlwaysPendingExecutor {
+        fn schema(&self) -> &[FieldType] {
+            &[]
+        }
+
+        fn next_batch(&mut self, _scan_rows: usize) -> BatchExecuteResult {
+            BatchExecuteResult {
+                physical_columns: LazyBatchColumnVec::empty(),
+                logical_rows: vec![0],
+                warnings: EvalWarnings::default(),
+                is_drained: Ok(false),
+            }
+        }
+
+        fn collect_statistics(&mut self, _destination: &mut BatchExecuteStatistics) {}
+    }
+
+    #[test]
+    fn next_batch_checked_short_circuits_when_already_cancelled() {
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+
+        let mut executor = AlwaysPendingExecutor;
+        let result = executor.next_batch_checked(10, &cancellation);
+
+        assert!(result.logical_rows.is_empty());
+        match result.is_drained {
+            Err(e) => assert!(e.is_cancelled()),
+            Ok(_) => panic!("expected Error::Cancelled"),
+        }
+    }
+
+    #[test]
+    fn next_batch_checked_forwards_to_next_batch_when_not_cancelled() {
+        let cancellation = CancellationToken::new();
+
+        let mut executor = AlwaysPendingExecutor;
+        let result = executor.next_batch_checked(10, &cancellation);
+
+        assert_eq!(result.logical_rows, vec![0]);
+        assert!(matches!(result.is_drained, Ok(false)));
+    }
+
+    #[test]
+    fn with_operator_context_annotates_plain_errors() {
+        struct FailingExecutor;
+        impl BatchExecutor for FailingExecutor {
+            fn schema(&self) -> &[FieldType] {
+                &[]
+            }
+            fn next_batch(&mut self, _scan_rows: usize) -> BatchExecuteResult {
+                BatchExecuteResult {
+                    physical_columns: LazyBatchColumnVec::empty(),
+                    logical_rows: Vec::new(),
+                    warnings: EvalWarnings::default(),
+                    is_drained: Err(Error::Other("boom".to_string().into())),
+                }
+            }
+            fn collect_statistics(&mut self, _destination: &mut BatchExecuteStatistics) {}
+        }
+
+        let mut executor = FailingExecutor.with_operator_context("TableScan");
+        let result = executor.next_batch(10);
+        assert_eq!(result.is_drained.unwrap_err().to_string(), "TableScan: boom");
+    }
+
+    #[test]
+    fn with_operator_context_leaves_cancellation_untouched() {
+        struct CancelledExecutor;
+        impl BatchExecutor for CancelledExecutor {
+            fn schema(&self) -> &[FieldType] {
+                &[]
+            }
+            fn next_batch(&mut self, _scan_rows: usize) -> BatchExecuteResult {
+                BatchExecuteResult {
+                    physical_columns: LazyBatchColumnVec::empty(),
+                    logical_rows: Vec::new(),
+                    warnings: EvalWarnings::default(),
+                    is_drained: Err(Error::Cancelled),
+                }
+            }
+            fn collect_statistics(&mut self, _destination: &mut BatchExecuteStatistics) {}
+        }
+
+        let mut executor = CancelledExecutor.with_operator_context("TableScan");
+        let result = executor.next_batch(10);
+        assert!(result.is_drained.unwrap_err().is_cancelled());
+    }
+}
@@ -0,0 +1,9 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! The batch (a.k.a. vectorized) coprocessor executor framework.
+
+pub mod cancellation;
+pub mod coordinator;
+pub mod executors;
+pub mod interface;
+pub mod spill;
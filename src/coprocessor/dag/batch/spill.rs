@@ -0,0 +1,276 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Arrow IPC (de)serialization of `BatchExecuteResult` plus an append-only spill file built on
+//! top of it, so that memory-bounded operators (sort, hash aggregation, hash join) have
+//! something to evict intermediate columnar data to.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Seek, SeekFrom};
+use std::path::Path;
+
+use arrow::datatypes::Schema as ArrowSchema;
+use arrow::ipc::reader::StreamReader;
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+use tipb::expression::FieldType;
+
+use crate::coprocessor::codec::batch::LazyBatchColumnVec;
+use crate::coprocessor::dag::batch::interface::{
+    BatchExecuteResult, BatchExecuteStatistics, BatchExecutor,
+};
+use crate::coprocessor::dag::expr::EvalWarnings;
+use crate::coprocessor::Error;
+
+/// Encodes `physical_columns` (restricted to the rows named by `logical_rows`, in logical
+/// order, so that filtered-out physical rows are never written to disk) as a single Arrow IPC
+/// record batch.
+///
+/// The TiKV `FieldType` of every column is carried in the Arrow schema's per-field metadata, so
+/// that `decode` can reconstruct `LazyBatchColumnVec` columns with identical types rather than
+/// relying on Arrow's own (coarser) type system.
+pub fn encode(
+    schema: &[FieldType],
+    physical_columns: &LazyBatchColumnVec,
+    logical_rows: &[usize],
+) -> Result<Vec<u8>, Error> {
+    let arrow_schema = arrow_schema_of(schema);
+    let record_batch =
+        physical_columns.to_arrow_record_batch(&arrow_schema, logical_rows)?;
+
+    let mut buffer = Vec::new();
+    {
+        let mut writer = StreamWriter::try_new(&mut buffer, &arrow_schema)
+            .map_err(|e| Error::Other(format!("arrow ipc writer: {}", e).into()))?;
+        writer
+            .write(&record_batch)
+            .map_err(|e| Error::Other(format!("arrow ipc write: {}", e).into()))?;
+        writer
+            .finish()
+            .map_err(|e| Error::Other(format!("arrow ipc finish: {}", e).into()))?;
+    }
+    Ok(buffer)
+}
+
+/// The inverse of `encode`: decodes an Arrow IPC byte stream back into a `LazyBatchColumnVec`,
+/// returned together with its row count. Every decoded row is a logical row, i.e. the logical
+/// rows are `0..rows` (the rows were already filtered before encoding, so there is nothing left
+/// to filter on the way back). Every column's `FieldType` is restored
+/// from the stream's own embedded schema metadata, not from an external schema, so the decoded
+/// columns are identical to the ones that were encoded even if the caller's schema has since
+/// drifted.
+pub fn decode(bytes: &[u8]) -> Result<(LazyBatchColumnVec, usize), Error> {
+    let mut reader = StreamReader::try_new(bytes)
+        .map_err(|e| Error::Other(format!("arrow ipc reader: {}", e).into()))?;
+    let record_batch = reader
+        .next()
+        .ok_or_else(|| Error::Other("spilled batch contains no data".to_string().into()))?
+        .map_err(|e| Error::Other(format!("arrow ipc read: {}", e).into()))?;
+    let rows = record_batch.num_rows();
+    let physical_columns = LazyBatchColumnVec::from_arrow_record_batch(&record_batch)?;
+    Ok((physical_columns, rows))
+}
+
+fn arrow_schema_of(schema: &[FieldType]) -> ArrowSchema {
+    LazyBatchColumnVec::arrow_schema_for_field_types(schema)
+}
+
+/// An append-only file of Arrow-IPC-encoded batches, written during a blocking operator's input
+/// phase once its in-memory budget is exceeded, and replayed as a `BatchExecutor` during the
+/// operator's output phase.
+pub struct SpillableBuffer {
+    schema: Vec<FieldType>,
+    writer: Option<BufWriter<File>>,
+    path: std::path::PathBuf,
+}
+
+impl SpillableBuffer {
+    /// Creates a new, empty spill file at `path`. The file is truncated if it already exists.
+    pub fn create(path: impl AsRef<Path>, schema: Vec<FieldType>) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::create(&path)
+            .map_err(|e| Error::Other(format!("create spill file: {}", e).into()))?;
+        Ok(SpillableBuffer {
+            schema,
+            writer: Some(BufWriter::new(file)),
+            path,
+        })
+    }
+
+    /// Appends one batch to the spill file. Each appended batch is length-prefixed so it can be
+    /// read back one at a time without needing an index.
+    pub fn append(
+        &mut self,
+        physical_columns: &LazyBatchColumnVec,
+        logical_rows: &[usize],
+    ) -> Result<(), Error> {
+        let encoded = encode(&self.schema, physical_columns, logical_rows)?;
+        let writer = self
+            .writer
+            .as_mut()
+            .expect("append() called after into_reader()");
+        use std::io::Write;
+        writer
+            .write_all(&(encoded.len() as u64).to_le_bytes())
+            .and_then(|_| writer.write_all(&encoded))
+            .map_err(|e| Error::Other(format!("write spill file: {}", e).into()))?;
+        Ok(())
+    }
+
+    /// Flushes and closes the write side, returning an executor that streams the spilled
+    /// batches back out in the order they were written.
+    pub fn into_reader(mut self) -> Result<SpillReader, Error> {
+        use std::io::Write;
+        let mut writer = self
+            .writer
+            .take()
+            .expect("into_reader() called more than once");
+        writer
+            .flush()
+            .map_err(|e| Error::Other(format!("flush spill file: {}", e).into()))?;
+        drop(writer);
+
+        let mut file = File::open(&self.path)
+            .map_err(|e| Error::Other(format!("reopen spill file: {}", e).into()))?;
+        file.seek(SeekFrom::Start(0))
+            .map_err(|e| Error::Other(format!("seek spill file: {}", e).into()))?;
+        Ok(SpillReader {
+            schema: self.schema,
+            reader: BufReader::new(file),
+        })
+    }
+}
+
+/// Streams previously spilled batches back out as a `BatchExecutor`.
+pub struct SpillReader {
+    schema: Vec<FieldType>,
+    reader: BufReader<File>,
+}
+
+impl BatchExecutor for SpillReader {
+    fn schema(&self) -> &[FieldType] {
+        &self.schema
+    }
+
+    fn next_batch(&mut self, _scan_rows: usize) -> BatchExecuteResult {
+        use std::io::{BufRead, Read};
+
+        let mut len_bytes = [0u8; 8];
+        match self.reader.read_exact(&mut len_bytes) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return BatchExecuteResult {
+                    physical_columns: LazyBatchColumnVec::empty(),
+                    logical_rows: Vec::new(),
+                    warnings: EvalWarnings::default(),
+                    is_drained: Ok(true),
+                };
+            }
+            Err(e) => {
+                return BatchExecuteResult {
+                    physical_columns: LazyBatchColumnVec::empty(),
+                    logical_rows: Vec::new(),
+                    warnings: EvalWarnings::default(),
+                    is_drained: Err(Error::Other(format!("read spill file: {}", e).into())),
+                };
+            }
+        }
+
+        let len = u64::from_le_bytes(len_bytes) as usize;
+        let mut buffer = vec![0u8; len];
+        if let Err(e) = self.reader.read_exact(&mut buffer) {
+            return BatchExecuteResult {
+                physical_columns: LazyBatchColumnVec::empty(),
+                logical_rows: Vec::new(),
+                warnings: EvalWarnings::default(),
+                is_drained: Err(Error::Other(format!("read spill file: {}", e).into())),
+            };
+        }
+
+        match decode(&buffer) {
+            Ok((physical_columns, rows)) => {
+                // The last spilled batch is reported as drained itself, rather than leaving that
+                // to a further call that would return no rows.
+                let at_end = self.reader.fill_buf().map_or(false, |rest| rest.is_empty());
+                BatchExecuteResult {
+                    physical_columns,
+                    logical_rows: (0..rows).collect(),
+                    warnings: EvalWarnings::default(),
+                    is_drained: Ok(at_end),
+                }
+            }
+            Err(e) => BatchExecuteResult {
+                physical_columns: LazyBatchColumnVec::empty(),
+                logical_rows: Vec::new(),
+                warnings: EvalWarnings::default(),
+                is_drained: Err(e),
+            },
+        }
+    }
+
+    fn collect_statistics(&mut self, _destination: &mut BatchExecuteStatistics) {
+        // A spilled batch carries no executor statistics of its own to report.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coprocessor::codec::batch::LazyBatchColumn;
+
+    fn test_schema() -> Vec<FieldType> {
+        vec![FieldType::new()]
+    }
+
+    fn single_column_batch(values: &[&[u8]]) -> (LazyBatchColumnVec, Vec<usize>) {
+        let mut column = LazyBatchColumn::new(FieldType::new());
+        for v in values {
+            column.push_raw(v.to_vec());
+        }
+        let logical_rows = (0..values.len()).collect();
+        (LazyBatchColumnVec::with_columns(vec![column]), logical_rows)
+    }
+
+    fn unique_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "tikv-batch-spill-test-{}-{}-{:?}",
+            name,
+            std::process::id(),
+            std::time::Instant::now()
+        ))
+    }
+
+    #[test]
+    fn encode_decode_round_trips_logical_rows_only() {
+        let (columns, all_rows) = single_column_batch(&[b"a", b"b", b"c"]);
+        // Only rows 2 and 0 are logical; row 1 ("b") is filtered out and must not survive.
+        let logical_rows = vec![all_rows[2], all_rows[0]];
+        let encoded = encode(&test_schema(), &columns, &logical_rows).unwrap();
+        let (decoded, rows) = decode(&encoded).unwrap();
+        assert_eq!(rows, 2);
+        assert_eq!(decoded[0].raw(), &[b"c".to_vec(), b"a".to_vec()]);
+    }
+
+    #[test]
+    fn spillable_buffer_round_trips_multiple_appended_batches() {
+        let path = unique_path("multi-batch");
+        let schema = test_schema();
+        let mut buffer = SpillableBuffer::create(&path, schema).unwrap();
+
+        let (first, first_rows) = single_column_batch(&[b"a", b"b"]);
+        buffer.append(&first, &first_rows).unwrap();
+        let (second, second_rows) = single_column_batch(&[b"c"]);
+        buffer.append(&second, &second_rows).unwrap();
+
+        let mut reader = buffer.into_reader().unwrap();
+
+        let result1 = reader.next_batch(1024);
+        assert!(matches!(result1.is_drained, Ok(false)));
+        assert_eq!(result1.physical_columns[0].raw(), &[b"a".to_vec(), b"b".to_vec()]);
+
+        let result2 = reader.next_batch(1024);
+        assert_eq!(result2.physical_columns[0].raw(), &[b"c".to_vec()]);
+        assert!(matches!(result2.is_drained, Ok(true)));
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,105 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! The error type shared by all coprocessor request handling, including the batch executor
+//! framework in `dag::batch`.
+
+use std::error::Error as StdError;
+use std::fmt::{self, Display, Formatter};
+
+/// Errors produced while building or running a coprocessor request.
+#[derive(Debug)]
+pub enum Error {
+    /// A catch-all for errors that don't otherwise have a dedicated variant, carrying the
+    /// underlying cause.
+    Other(Box<dyn StdError + Send + Sync>),
+
+    /// The request was cancelled (deadline exceeded, or explicitly killed) before it finished
+    /// running. Distinguished from `Other` so that callers can special-case it, e.g. to avoid
+    /// counting a cancelled request as a query failure.
+    Cancelled,
+
+    /// Wraps another `Error` with the name of the executor/operator that produced it, so that a
+    /// deep executor tree can report which stage actually failed instead of only the underlying
+    /// cause. See `BatchExecutor::with_operator_context`.
+    WithOperatorContext {
+        operator_name: &'static str,
+        source: Box<Error>,
+    },
+}
+
+impl Error {
+    /// Wraps `self` with `operator_name`, unless it already is (or is wrapping) a `Cancelled`
+    /// error, in which case it is returned unchanged: cancellation is a distinct, recognizable
+    /// outcome in its own right and should keep round-tripping as exactly `Error::Cancelled`
+    /// rather than accumulating operator context.
+    pub fn attach_operator(self, operator_name: &'static str) -> Error {
+        if self.is_cancelled() {
+            return self;
+        }
+        Error::WithOperatorContext {
+            operator_name,
+            source: Box::new(self),
+        }
+    }
+
+    /// Whether this error is (or wraps) a cancellation.
+    pub fn is_cancelled(&self) -> bool {
+        match self {
+            Error::Cancelled => true,
+            Error::WithOperatorContext { source, .. } => source.is_cancelled(),
+            Error::Other(_) => false,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Other(err) => write!(f, "{}", err),
+            Error::Cancelled => write!(f, "request is cancelled"),
+            Error::WithOperatorContext {
+                operator_name,
+                source,
+            } => write!(f, "{}: {}", operator_name, source),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Other(err) => err.source(),
+            Error::Cancelled => None,
+            Error::WithOperatorContext { source, .. } => Some(source.as_ref()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attach_operator_wraps_non_cancelled_errors() {
+        let err = Error::Other("boom".to_string().into()).attach_operator("TableScan");
+        assert_eq!(err.to_string(), "TableScan: boom");
+        assert!(!err.is_cancelled());
+    }
+
+    #[test]
+    fn attach_operator_leaves_cancelled_errors_untouched() {
+        let err = Error::Cancelled.attach_operator("TableScan");
+        assert!(err.is_cancelled());
+        assert_eq!(err.to_string(), "request is cancelled");
+    }
+
+    #[test]
+    fn attach_operator_sees_through_existing_context() {
+        let err = Error::WithOperatorContext {
+            operator_name: "Selection",
+            source: Box::new(Error::Cancelled),
+        }
+        .attach_operator("TableScan");
+        assert!(err.is_cancelled());
+    }
+}